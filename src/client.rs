@@ -2,15 +2,38 @@ use anyhow::Context;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use crate::{
-    consts, read_exact, util::{
+    consts, util::{
         stream::tcp_connect,
         target_addr::{TargetAddr, ToTargetAddr},
     }, AuthenticationMethod, ReplyError, Result, Socks5Command, SocksError
 };
 
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
+use std::time::Duration;
 
-use tokio::net::TcpStream;
+use tokio::net::{TcpStream, UdpSocket};
+
+/// Runs `$fut` as-is, unless `$config.connect_timeout` is set, in which case it
+/// is raced against that deadline and `ReplyError::ConnectionTimeout` is
+/// returned on elapse.
+macro_rules! with_timeout {
+    ($config:expr, $fut:expr) => {{
+        match $config.connect_timeout {
+            Some(secs) => tokio::time::timeout(Duration::from_secs(secs), $fut)
+                .await
+                .map_err(|_| SocksError::from(ReplyError::ConnectionTimeout))?,
+            None => $fut.await,
+        }
+    }};
+}
+
+/// Same as [`read_exact`](crate::read_exact), but honors `$self.config.connect_timeout`.
+macro_rules! read_exact_timeout {
+    ($self:expr, $array:expr) => {{
+        let mut x = $array;
+        with_timeout!($self.config, $self.socket.read_exact(&mut x)).map(|_| x)
+    }};
+}
 
 /// 客户端的一些基本设置
 #[derive(Debug)]
@@ -54,6 +77,22 @@ pub struct Socks5Stream<S: AsyncRead + AsyncWrite + Unpin> {
 }
 
 /// Api if you want to use TcpStream to create a new connection to the SOCKS5 server.
+/// Resolves `socks_server` and connects to it, honoring `config`'s
+/// `connect_timeout`. Shared by every entry point that opens a fresh
+/// connection to a SOCKS5 server.
+async fn connect_to_proxy<T>(socks_server: T, config: &Config) -> Result<TcpStream>
+where
+    T: ToSocketAddrs,
+{
+    let addr = socks_server
+        .to_socket_addrs()?
+        .next()
+        .context("unreachable")?;
+    let socket = with_timeout!(config, tcp_connect(addr))?;
+    info!("Connected @ {}", &socket.peer_addr()?);
+    Ok(socket)
+}
+
 impl Socks5Stream<TcpStream> {
     /// Connects to a target server through a SOCKS5 proxy.
     pub async fn connect<T>(
@@ -89,12 +128,7 @@ impl Socks5Stream<TcpStream> {
     where
         T: ToSocketAddrs,
     {
-        let addr = socks_server
-            .to_socket_addrs()?
-            .next()
-            .context("unreachable")?;
-        let socket = tcp_connect(addr).await?;
-        info!("Connected @ {}", &socket.peer_addr()?);
+        let socket = connect_to_proxy(socks_server, &config).await?;
 
         // Specify the target, here domain name, dns will be resolved on the server side
         let target_addr = (target_addr.as_str(), target_port)
@@ -107,6 +141,58 @@ impl Socks5Stream<TcpStream> {
 
         Ok(socks_stream)
     }
+
+    /// Resolve a domain name to an IP address through a SOCKS5 server that
+    /// implements the Tor `RESOLVE` extension (e.g. a local `tor` SocksPort).
+    pub async fn resolve<T>(socks_server: T, domain: String, config: Config) -> Result<IpAddr>
+    where
+        T: ToSocketAddrs,
+    {
+        let socket = connect_to_proxy(socks_server, &config).await?;
+
+        // Port is ignored by RESOLVE, only the domain in DST.ADDR matters.
+        let target_addr = TargetAddr::Domain(domain, 0);
+
+        let mut socks_stream = Self::use_stream(socket, None, config).await?;
+        let bind_addr = socks_stream
+            .request(Socks5Command::TorResolve, target_addr)
+            .await?;
+
+        match bind_addr {
+            TargetAddr::Ip(addr) => Ok(addr.ip()),
+            TargetAddr::Domain(domain, _) => Err(anyhow::Error::msg(format!(
+                "Expected an IP address in RESOLVE reply, got domain `{}`",
+                domain
+            ))
+            .into()),
+        }
+    }
+
+    /// Reverse-resolve an IP address to a domain name through a SOCKS5 server
+    /// that implements the Tor `RESOLVE_PTR` extension.
+    pub async fn resolve_ptr<T>(socks_server: T, ip: IpAddr, config: Config) -> Result<String>
+    where
+        T: ToSocketAddrs,
+    {
+        let socket = connect_to_proxy(socks_server, &config).await?;
+
+        // Port is ignored by RESOLVE_PTR, only the address in DST.ADDR matters.
+        let target_addr = TargetAddr::Ip(SocketAddr::new(ip, 0));
+
+        let mut socks_stream = Self::use_stream(socket, None, config).await?;
+        let bind_addr = socks_stream
+            .request(Socks5Command::TorResolvePtr, target_addr)
+            .await?;
+
+        match bind_addr {
+            TargetAddr::Domain(domain, _) => Ok(domain),
+            TargetAddr::Ip(addr) => Err(anyhow::Error::msg(format!(
+                "Expected a domain name in RESOLVE_PTR reply, got IP `{}`",
+                addr
+            ))
+            .into()),
+        }
+    }
 }
 
 impl<S> Socks5Stream<S>
@@ -137,13 +223,129 @@ where
         // Handshake Lifecycle
         if !stream.config.skip_auth {
             debug!("to auth");
-            // let methods = stream.send_version_and_methods(methods).await?;
-            // stream.which_method_accepted(methods).await?;
+            let methods = stream.send_version_and_methods(methods).await?;
+            stream.which_method_accepted(methods).await?;
         } else {
             debug!("skipping auth");
         }
         Ok(stream)
     }
+
+    /// Send the version/methods greeting to the server.
+    ///
+    /// # Request
+    /// ```test
+    ///          +----+----------+----------+
+    ///          |VER | NMETHODS | METHODS  |
+    ///          +----+----------+----------+
+    ///          | 1  |    1     | 1 to 255 |
+    ///          +----+----------+----------+
+    /// ```
+    async fn send_version_and_methods(
+        &mut self,
+        methods: Vec<AuthenticationMethod>,
+    ) -> Result<Vec<AuthenticationMethod>> {
+        debug!("Send version and methods, methods supported: {:?}", &methods);
+
+        let mut packet = Vec::with_capacity(2 + methods.len());
+        packet.push(consts::SOCKS5_VERSION);
+        packet.push(methods.len() as u8);
+        for method in &methods {
+            packet.push(method.as_u8());
+        }
+
+        with_timeout!(self.config, self.socket.write_all(&packet))
+            .context("Can't write version and methods packet.")?;
+
+        with_timeout!(self.config, self.socket.flush())
+            .context("Can't flush version and methods packet.")?;
+
+        Ok(methods)
+    }
+
+    /// Read the server's method selection and, if required, run the matching
+    /// subnegotiation (only username/password, RFC 1929, is supported today).
+    ///
+    /// # Response
+    /// ```test
+    ///          +----+--------+
+    ///          |VER | METHOD |
+    ///          +----+--------+
+    ///          | 1  |   1    |
+    ///          +----+--------+
+    /// ```
+    async fn which_method_accepted(&mut self, methods: Vec<AuthenticationMethod>) -> Result<()> {
+        let [version, method] = read_exact_timeout!(self, [0u8; 2])
+            .context("Can't read method selected by the server.")?;
+
+        if version != consts::SOCKS5_VERSION {
+            return Err(SocksError::UnsupportedSocksVersion(version));
+        }
+
+        match method {
+            consts::SOCKS5_AUTH_METHOD_NONE => {
+                debug!("No auth method accepted.");
+                Ok(())
+            }
+            consts::SOCKS5_AUTH_METHOD_PASSWORD => {
+                debug!("Password auth method accepted.");
+                let method = methods
+                    .into_iter()
+                    .find(|m| matches!(m, AuthenticationMethod::Password { .. }))
+                    .context("Server asked for password auth but none was provided")?;
+
+                if let AuthenticationMethod::Password { username, password } = method {
+                    self.password_auth(username, password).await?;
+                }
+
+                Ok(())
+            }
+            consts::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE => Err(SocksError::NoAcceptableAuthMethod),
+            _ => Err(anyhow::Error::msg(format!("Unsupported auth method `{}`.", method)).into()),
+        }
+    }
+
+    /// Perform the RFC 1929 username/password subnegotiation.
+    ///
+    /// # Request
+    /// ```test
+    ///          +----+------+----------+------+----------+
+    ///          |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+    ///          +----+------+----------+------+----------+
+    ///          | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+    ///          +----+------+----------+------+----------+
+    /// ```
+    async fn password_auth(&mut self, username: String, password: String) -> Result<()> {
+        if username.len() > u8::MAX as usize {
+            return Err(anyhow::Error::msg("Username longer than 255 bytes").into());
+        }
+        if password.len() > u8::MAX as usize {
+            return Err(anyhow::Error::msg("Password longer than 255 bytes").into());
+        }
+
+        let mut packet = Vec::with_capacity(3 + username.len() + password.len());
+        packet.push(consts::SOCKS5_AUTH_PASSWORD_VERSION);
+        packet.push(username.len() as u8);
+        packet.extend_from_slice(username.as_bytes());
+        packet.push(password.len() as u8);
+        packet.extend_from_slice(password.as_bytes());
+
+        with_timeout!(self.config, self.socket.write_all(&packet))
+            .context("Can't write username/password packet.")?;
+
+        with_timeout!(self.config, self.socket.flush())
+            .context("Can't flush username/password packet.")?;
+
+        let [_version, status] =
+            read_exact_timeout!(self, [0u8; 2]).context("Can't read username/password reply.")?;
+
+        if status != 0x00 {
+            return Err(SocksError::AuthenticationRejected);
+        }
+
+        Ok(())
+    }
+
     /// 2
     pub async fn request(
         &mut self,
@@ -211,7 +413,13 @@ where
                     // port
                 }
                 TargetAddr::Ip(SocketAddr::V6(addr)) => {
-                    return Err(anyhow::Error::msg("unsupported ipv6").into());
+                    debug!("TargetAddr::IpV6");
+                    padding = 22;
+
+                    packet[3] = 0x04;
+                    packet[4..20].copy_from_slice(&(addr.ip()).octets()); // ip
+                    packet[20..padding].copy_from_slice(&addr.port().to_be_bytes());
+                    // port
                 }
                 TargetAddr::Domain(ref domain, port) => {
                     debug!("TargetAddr::Domain");
@@ -235,14 +443,10 @@ where
 
         // we limit the end of the packet right after the domain + port number, we don't need to print
         // useless 0 bytes, otherwise other protocol won't understand the request (like HTTP servers).
-        self.socket
-            .write(&packet[..padding])
-            .await
+        with_timeout!(self.config, self.socket.write(&packet[..padding]))
             .context("Can't write request header's packet.")?;
 
-        self.socket
-            .flush()
-            .await
+        with_timeout!(self.config, self.socket.flush())
             .context("Can't flush request header's packet")?;
 
         Ok(())
@@ -252,7 +456,7 @@ where
     /// remote server.
     async fn read_request_reply(&mut self) -> Result<TargetAddr> {
         let [version, reply, rsv, address_type] =
-            read_exact!(self.socket, [0u8; 4]).context("Received malformed reply")?;
+            read_exact_timeout!(self, [0u8; 4]).context("Received malformed reply")?;
         debug!(
                 "Reply received: [version: {version}, reply: {reply}, rsv: {rsv}, address_type: {address_type}]",
                 version = version,
@@ -267,8 +471,520 @@ where
         if reply != consts::SOCKS5_REPLY_SUCCEEDED {
             return Err(ReplyError::from_u8(reply).into()); // Convert reply received into correct error
         }
-        todo!()
+
+        // Reply's BND.ADDR, format depends on the received address type
+        let bind_addr = match address_type {
+            consts::SOCKS5_ADDR_TYPE_IPV4 => {
+                let ip = Ipv4Addr::from(
+                    read_exact_timeout!(self, [0u8; 4]).context("Malformed IPv4 bind address")?,
+                );
+                let port = u16::from_be_bytes(
+                    read_exact_timeout!(self, [0u8; 2]).context("Malformed bind port")?,
+                );
+                TargetAddr::Ip(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+            }
+            consts::SOCKS5_ADDR_TYPE_IPV6 => {
+                let ip = Ipv6Addr::from(
+                    read_exact_timeout!(self, [0u8; 16]).context("Malformed IPv6 bind address")?,
+                );
+                let port = u16::from_be_bytes(
+                    read_exact_timeout!(self, [0u8; 2]).context("Malformed bind port")?,
+                );
+                TargetAddr::Ip(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)))
+            }
+            consts::SOCKS5_ADDR_TYPE_DOMAIN_NAME => {
+                let [len] =
+                    read_exact_timeout!(self, [0u8; 1]).context("Malformed domain length")?;
+                let mut domain = vec![0u8; len as usize];
+                with_timeout!(self.config, self.socket.read_exact(&mut domain))
+                    .context("Malformed domain bind address")?;
+                let domain = String::from_utf8(domain)
+                    .map_err(|_| anyhow::Error::msg("Bind domain isn't valid UTF-8"))?;
+                let port = u16::from_be_bytes(
+                    read_exact_timeout!(self, [0u8; 2]).context("Malformed bind port")?,
+                );
+                TargetAddr::Domain(domain, port)
+            }
+            _ => return Err(ReplyError::AddressTypeNotSupported.into()),
+        };
+
+        debug!("Bind address received: {:?}", &bind_addr);
+
+        Ok(bind_addr)
     }
 }
 
 const MAX_ADDR_LEN: usize = 260;
+
+/// A SOCKS5 UDP relay session, created through a `UDP ASSOCIATE` request.
+///
+/// The control `TcpStream` is kept alive for the lifetime of this struct: the
+/// proxy tears down the UDP association as soon as it notices the control
+/// connection has closed.
+#[derive(Debug)]
+pub struct Socks5Datagram {
+    socket: UdpSocket,
+    // Held so the SOCKS5 server doesn't tear down the UDP association.
+    #[allow(dead_code)]
+    stream: Socks5Stream<TcpStream>,
+}
+
+impl Socks5Datagram {
+    /// Creates a UDP ASSOCIATE session with `socks_server`, binding the local
+    /// relay socket to `client_addr`.
+    pub async fn bind<T, U>(socks_server: T, client_addr: U, config: Config) -> Result<Self>
+    where
+        T: ToSocketAddrs,
+        U: tokio::net::ToSocketAddrs,
+    {
+        let socket = connect_to_proxy(socks_server, &config).await?;
+
+        let mut stream = Socks5Stream::use_stream(socket, None, config).await?;
+
+        // We don't know the client's UDP address yet, so send 0.0.0.0:0 and let
+        // the server fill the blanks (the same zeros `request_header` falls
+        // back to when no target is set).
+        let target_addr = TargetAddr::Ip(SocketAddr::from(([0, 0, 0, 0], 0)));
+        let relay_addr = stream
+            .request(Socks5Command::UDPAssociate, target_addr)
+            .await?;
+
+        let relay_addr = match relay_addr {
+            TargetAddr::Ip(addr) => addr,
+            TargetAddr::Domain(domain, port) => (domain.as_str(), port)
+                .to_socket_addrs()?
+                .next()
+                .context("Can't resolve UDP relay's domain address")?,
+        };
+
+        let socket = UdpSocket::bind(client_addr).await?;
+        socket.connect(relay_addr).await?;
+
+        Ok(Socks5Datagram { socket, stream })
+    }
+
+    /// Sends `buf` to `target`, prepending the SOCKS5 UDP request header.
+    ///
+    /// # Packet
+    /// ```test
+    ///          +----+------+------+----------+----------+----------+
+    ///          |RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
+    ///          +----+------+------+----------+----------+----------+
+    ///          | 2  |  1   |  1   | Variable |    2     | Variable |
+    ///          +----+------+------+----------+----------+----------+
+    /// ```
+    pub async fn send_to(&self, buf: &[u8], target: TargetAddr) -> Result<usize> {
+        let packet = encode_udp_header(&target, buf)?;
+
+        self.socket
+            .send(&packet)
+            .await
+            .context("Can't send UDP datagram to relay")
+            .map_err(Into::into)
+    }
+
+    /// Receives a datagram from the relay into `buf`, stripping the SOCKS5 UDP
+    /// header, and returns the number of bytes written plus the origin address.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, TargetAddr)> {
+        let mut packet = [0u8; u16::MAX as usize + 1];
+        let n = self
+            .socket
+            .recv(&mut packet)
+            .await
+            .context("Can't receive UDP datagram from relay")?;
+
+        let (origin, payload) = decode_udp_header(&packet[..n])?;
+
+        if payload.len() > buf.len() {
+            return Err(
+                anyhow::Error::msg("Provided buffer too small for received UDP datagram").into(),
+            );
+        }
+        buf[..payload.len()].copy_from_slice(payload);
+
+        Ok((payload.len(), origin))
+    }
+}
+
+/// Builds a SOCKS5 UDP relay packet carrying `payload` addressed to `target`.
+///
+/// # Packet
+/// ```test
+///          +----+------+------+----------+----------+----------+
+///          |RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
+///          +----+------+------+----------+----------+----------+
+///          | 2  |  1   |  1   | Variable |    2     | Variable |
+///          +----+------+------+----------+----------+----------+
+/// ```
+fn encode_udp_header(target: &TargetAddr, payload: &[u8]) -> Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(MAX_ADDR_LEN + payload.len());
+    packet.extend_from_slice(&[0x00, 0x00, 0x00]); // RSV + FRAG (fragmentation unsupported)
+
+    match target {
+        TargetAddr::Ip(SocketAddr::V4(addr)) => {
+            packet.push(consts::SOCKS5_ADDR_TYPE_IPV4);
+            packet.extend_from_slice(&addr.ip().octets());
+            packet.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        TargetAddr::Ip(SocketAddr::V6(addr)) => {
+            packet.push(consts::SOCKS5_ADDR_TYPE_IPV6);
+            packet.extend_from_slice(&addr.ip().octets());
+            packet.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        TargetAddr::Domain(domain, port) => {
+            if domain.len() > u8::MAX as usize {
+                return Err(SocksError::ExceededMaxDomainLen(domain.len()));
+            }
+            packet.push(consts::SOCKS5_ADDR_TYPE_DOMAIN_NAME);
+            packet.push(domain.len() as u8);
+            packet.extend_from_slice(domain.as_bytes());
+            packet.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+
+    packet.extend_from_slice(payload);
+
+    Ok(packet)
+}
+
+/// Parses a SOCKS5 UDP relay packet, returning the origin address and a slice
+/// of `packet` holding the payload. Every field is bounds-checked: a
+/// truncated or malformed `packet` yields an `Err` rather than panicking.
+fn decode_udp_header(packet: &[u8]) -> Result<(TargetAddr, &[u8])> {
+    if packet.len() < 4 {
+        return Err(anyhow::Error::msg("UDP relay datagram shorter than its header").into());
+    }
+    if packet[2] != 0x00 {
+        return Err(anyhow::Error::msg("Fragmented UDP datagrams aren't supported").into());
+    }
+
+    let address_type = packet[3];
+    let mut cursor = 4;
+    let origin = match address_type {
+        consts::SOCKS5_ADDR_TYPE_IPV4 => {
+            if packet.len() < cursor + 6 {
+                return Err(anyhow::Error::msg("Truncated IPv4 address in UDP relay datagram").into());
+            }
+            let ip = Ipv4Addr::new(
+                packet[cursor],
+                packet[cursor + 1],
+                packet[cursor + 2],
+                packet[cursor + 3],
+            );
+            cursor += 4;
+            let port = u16::from_be_bytes([packet[cursor], packet[cursor + 1]]);
+            cursor += 2;
+            TargetAddr::Ip(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+        }
+        consts::SOCKS5_ADDR_TYPE_IPV6 => {
+            if packet.len() < cursor + 18 {
+                return Err(anyhow::Error::msg("Truncated IPv6 address in UDP relay datagram").into());
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&packet[cursor..cursor + 16]);
+            cursor += 16;
+            let ip = Ipv6Addr::from(octets);
+            let port = u16::from_be_bytes([packet[cursor], packet[cursor + 1]]);
+            cursor += 2;
+            TargetAddr::Ip(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)))
+        }
+        consts::SOCKS5_ADDR_TYPE_DOMAIN_NAME => {
+            if packet.len() < cursor + 1 {
+                return Err(
+                    anyhow::Error::msg("Truncated domain length in UDP relay datagram").into(),
+                );
+            }
+            let len = packet[cursor] as usize;
+            cursor += 1;
+            if packet.len() < cursor + len + 2 {
+                return Err(anyhow::Error::msg("Truncated domain address in UDP relay datagram").into());
+            }
+            let domain = String::from_utf8(packet[cursor..cursor + len].to_vec())
+                .map_err(|_| anyhow::Error::msg("Origin domain isn't valid UTF-8"))?;
+            cursor += len;
+            let port = u16::from_be_bytes([packet[cursor], packet[cursor + 1]]);
+            cursor += 2;
+            TargetAddr::Domain(domain, port)
+        }
+        _ => return Err(ReplyError::AddressTypeNotSupported.into()),
+    };
+
+    Ok((origin, &packet[cursor..]))
+}
+
+#[cfg(test)]
+mod udp_header_tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_ipv4_target() {
+        let target = TargetAddr::Ip("127.0.0.1:1234".parse().unwrap());
+        let packet = encode_udp_header(&target, b"hello").unwrap();
+
+        let (origin, payload) = decode_udp_header(&packet).unwrap();
+        assert_eq!(origin, target);
+        assert_eq!(payload, b"hello");
+    }
+
+    #[test]
+    fn round_trips_ipv6_target() {
+        let target = TargetAddr::Ip("[::1]:1234".parse().unwrap());
+        let packet = encode_udp_header(&target, b"hello").unwrap();
+
+        let (origin, payload) = decode_udp_header(&packet).unwrap();
+        assert_eq!(origin, target);
+        assert_eq!(payload, b"hello");
+    }
+
+    #[test]
+    fn round_trips_domain_target() {
+        let target = TargetAddr::Domain("example.com".to_owned(), 443);
+        let packet = encode_udp_header(&target, b"payload").unwrap();
+
+        let (origin, payload) = decode_udp_header(&packet).unwrap();
+        assert_eq!(origin, target);
+        assert_eq!(payload, b"payload");
+    }
+
+    #[test]
+    fn rejects_truncated_packet_instead_of_panicking() {
+        let target = TargetAddr::Ip("127.0.0.1:1234".parse().unwrap());
+        let packet = encode_udp_header(&target, b"hello").unwrap();
+
+        // Cut the packet off mid-address: no panic, just an Err.
+        assert!(decode_udp_header(&packet[..6]).is_err());
+        // Header present but nothing else at all.
+        assert!(decode_udp_header(&packet[..4]).is_err());
+        // Not even a full header.
+        assert!(decode_udp_header(&packet[..2]).is_err());
+    }
+
+    #[test]
+    fn rejects_domain_length_with_no_domain_bytes() {
+        // RSV(2) + FRAG(1) + ATYP=domain(1) + len=10, but no domain bytes follow.
+        let packet = [0x00, 0x00, 0x00, consts::SOCKS5_ADDR_TYPE_DOMAIN_NAME, 10];
+        assert!(decode_udp_header(&packet).is_err());
+    }
+}
+
+#[cfg(test)]
+mod reply_tests {
+    use super::*;
+
+    fn test_stream(socket: tokio::io::DuplexStream) -> Socks5Stream<tokio::io::DuplexStream> {
+        Socks5Stream {
+            socket,
+            config: Config::default(),
+            target_addr: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn read_request_reply_parses_ipv4() {
+        let (client_io, mut server_io) = tokio::io::duplex(64);
+        let mut stream = test_stream(client_io);
+
+        server_io
+            .write_all(&[0x05, 0x00, 0x00, consts::SOCKS5_ADDR_TYPE_IPV4, 127, 0, 0, 1, 0x1f, 0x90])
+            .await
+            .unwrap();
+
+        let bind_addr = stream.read_request_reply().await.unwrap();
+        assert_eq!(bind_addr, TargetAddr::Ip("127.0.0.1:8080".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn read_request_reply_parses_ipv6() {
+        let (client_io, mut server_io) = tokio::io::duplex(64);
+        let mut stream = test_stream(client_io);
+
+        let mut packet = vec![0x05, 0x00, 0x00, consts::SOCKS5_ADDR_TYPE_IPV6];
+        packet.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        packet.extend_from_slice(&443u16.to_be_bytes());
+        server_io.write_all(&packet).await.unwrap();
+
+        let bind_addr = stream.read_request_reply().await.unwrap();
+        assert_eq!(bind_addr, TargetAddr::Ip("[::1]:443".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn read_request_reply_parses_domain() {
+        let (client_io, mut server_io) = tokio::io::duplex(64);
+        let mut stream = test_stream(client_io);
+
+        let domain = b"example.com";
+        let mut packet = vec![0x05, 0x00, 0x00, consts::SOCKS5_ADDR_TYPE_DOMAIN_NAME, domain.len() as u8];
+        packet.extend_from_slice(domain);
+        packet.extend_from_slice(&80u16.to_be_bytes());
+        server_io.write_all(&packet).await.unwrap();
+
+        let bind_addr = stream.read_request_reply().await.unwrap();
+        assert_eq!(bind_addr, TargetAddr::Domain("example.com".to_owned(), 80));
+    }
+
+    #[tokio::test]
+    async fn read_request_reply_maps_error_codes() {
+        let (client_io, mut server_io) = tokio::io::duplex(64);
+        let mut stream = test_stream(client_io);
+
+        server_io
+            .write_all(&[0x05, consts::SOCKS5_REPLY_HOST_UNREACHABLE, 0x00, consts::SOCKS5_ADDR_TYPE_IPV4])
+            .await
+            .unwrap();
+
+        let err = stream.read_request_reply().await.unwrap_err();
+        assert!(matches!(err, SocksError::ReplyError(ReplyError::HostUnreachable)));
+    }
+
+    #[tokio::test]
+    async fn request_header_encodes_ipv6_target() {
+        let (client_io, mut server_io) = tokio::io::duplex(64);
+        let mut stream = test_stream(client_io);
+        stream.target_addr = Some(TargetAddr::Ip("[::1]:443".parse().unwrap()));
+
+        stream
+            .request_header(Socks5Command::TCPConnect)
+            .await
+            .unwrap();
+
+        let mut packet = [0u8; 22];
+        server_io.read_exact(&mut packet).await.unwrap();
+
+        assert_eq!(packet[0], consts::SOCKS5_VERSION);
+        assert_eq!(packet[1], consts::SOCKS5_CMD_TCP_CONNECT);
+        assert_eq!(packet[2], 0x00);
+        assert_eq!(packet[3], consts::SOCKS5_ADDR_TYPE_IPV6);
+        assert_eq!(&packet[4..20], &Ipv6Addr::LOCALHOST.octets());
+        assert_eq!(u16::from_be_bytes([packet[20], packet[21]]), 443);
+    }
+
+    #[test]
+    fn reply_error_from_u8_maps_known_and_unknown_codes() {
+        assert!(matches!(ReplyError::from_u8(0x00), ReplyError::Succeeded));
+        assert!(matches!(ReplyError::from_u8(0x01), ReplyError::GeneralFailure));
+        assert!(matches!(ReplyError::from_u8(0x02), ReplyError::ConnectionNotAllowed));
+        assert!(matches!(ReplyError::from_u8(0x03), ReplyError::NetworkUnreachable));
+        assert!(matches!(ReplyError::from_u8(0x04), ReplyError::HostUnreachable));
+        assert!(matches!(ReplyError::from_u8(0x05), ReplyError::ConnectionRefused));
+        assert!(matches!(ReplyError::from_u8(0x06), ReplyError::TtlExpired));
+        assert!(matches!(ReplyError::from_u8(0x07), ReplyError::CommandNotSupported));
+        assert!(matches!(ReplyError::from_u8(0x08), ReplyError::AddressTypeNotSupported));
+        assert!(matches!(ReplyError::from_u8(0x09), ReplyError::OtherReply(0x09)));
+    }
+}
+
+#[cfg(test)]
+mod resolve_tests {
+    use super::*;
+    use crate::read_exact;
+    use tokio::net::TcpListener;
+
+    /// Drives the server side of the handshake plus one Tor RESOLVE/RESOLVE_PTR
+    /// request, replying with the bytes the caller supplies as `reply`.
+    async fn fake_resolve_server(
+        listener: TcpListener,
+        expected_cmd: u8,
+        reply: Vec<u8>,
+    ) -> (u8, TargetAddr) {
+        let (mut socket, _) = listener.accept().await.unwrap();
+
+        let [_version, nmethods] = read_exact!(socket, [0u8; 2]).unwrap();
+        let mut methods = vec![0u8; nmethods as usize];
+        socket.read_exact(&mut methods).await.unwrap();
+        socket
+            .write_all(&[consts::SOCKS5_VERSION, consts::SOCKS5_AUTH_METHOD_NONE])
+            .await
+            .unwrap();
+
+        let [_version, cmd, _rsv, address_type] = read_exact!(socket, [0u8; 4]).unwrap();
+        assert_eq!(cmd, expected_cmd);
+
+        let target_addr = match address_type {
+            consts::SOCKS5_ADDR_TYPE_IPV4 => {
+                let ip = Ipv4Addr::from(read_exact!(socket, [0u8; 4]).unwrap());
+                let port = u16::from_be_bytes(read_exact!(socket, [0u8; 2]).unwrap());
+                TargetAddr::Ip(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+            }
+            consts::SOCKS5_ADDR_TYPE_DOMAIN_NAME => {
+                let [len] = read_exact!(socket, [0u8; 1]).unwrap();
+                let mut domain = vec![0u8; len as usize];
+                socket.read_exact(&mut domain).await.unwrap();
+                let port = u16::from_be_bytes(read_exact!(socket, [0u8; 2]).unwrap());
+                TargetAddr::Domain(String::from_utf8(domain).unwrap(), port)
+            }
+            other => panic!("unexpected address type in test request: {}", other),
+        };
+
+        socket.write_all(&reply).await.unwrap();
+
+        (cmd, target_addr)
+    }
+
+    #[tokio::test]
+    async fn resolve_round_trips_domain_to_ip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let reply = vec![
+            0x05,
+            consts::SOCKS5_REPLY_SUCCEEDED,
+            0x00,
+            consts::SOCKS5_ADDR_TYPE_IPV4,
+            93,
+            184,
+            216,
+            34,
+            0x00,
+            0x00,
+        ];
+        let server = tokio::spawn(fake_resolve_server(
+            listener,
+            consts::SOCKS5_CMD_TOR_RESOLVE,
+            reply,
+        ));
+
+        let ip = Socks5Stream::resolve(addr, "example.com".to_owned(), Config::default())
+            .await
+            .unwrap();
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)));
+
+        let (cmd, target_addr) = server.await.unwrap();
+        assert_eq!(cmd, consts::SOCKS5_CMD_TOR_RESOLVE);
+        assert_eq!(
+            target_addr,
+            TargetAddr::Domain("example.com".to_owned(), 0)
+        );
+    }
+
+    #[tokio::test]
+    async fn resolve_ptr_round_trips_ip_to_domain() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let domain = b"example.com";
+        let mut reply = vec![
+            0x05,
+            consts::SOCKS5_REPLY_SUCCEEDED,
+            0x00,
+            consts::SOCKS5_ADDR_TYPE_DOMAIN_NAME,
+            domain.len() as u8,
+        ];
+        reply.extend_from_slice(domain);
+        reply.extend_from_slice(&0u16.to_be_bytes());
+        let server = tokio::spawn(fake_resolve_server(
+            listener,
+            consts::SOCKS5_CMD_TOR_RESOLVE_PTR,
+            reply,
+        ));
+
+        let ip = IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34));
+        let resolved = Socks5Stream::resolve_ptr(addr, ip, Config::default())
+            .await
+            .unwrap();
+        assert_eq!(resolved, "example.com");
+
+        let (cmd, target_addr) = server.await.unwrap();
+        assert_eq!(cmd, consts::SOCKS5_CMD_TOR_RESOLVE_PTR);
+        assert_eq!(target_addr, TargetAddr::Ip(SocketAddr::new(ip, 0)));
+    }
+}
@@ -23,6 +23,12 @@ pub enum SocksError {
     /// 5
     #[error("Unsupported SOCKS version `{0}`.")]
     UnsupportedSocksVersion(u8),
+    /// 6
+    #[error("No acceptable auth method, server returned 0xff.")]
+    NoAcceptableAuthMethod,
+    /// 7
+    #[error("Username/password authentication failed")]
+    AuthenticationRejected,
 }
 
 pub type Result<T, E = SocksError> = core::result::Result<T, E>;
@@ -31,15 +37,21 @@ pub type Result<T, E = SocksError> = core::result::Result<T, E>;
 pub mod client;
 /// 2
 pub mod util;
+/// 3
+pub mod server;
 
 #[derive(Debug, PartialEq)]
 pub enum Socks5Command {
     TCPConnect,
     TCPBind,
     UDPAssociate,
+    /// Tor-specific extension: resolve a domain name to an IP address.
+    TorResolve,
+    /// Tor-specific extension: reverse-resolve an IP address to a domain name.
+    TorResolvePtr,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum AuthenticationMethod {
     None,
     Password { username: String, password: String },
@@ -68,7 +80,8 @@ pub enum ReplyError {
     CommandNotSupported,
     #[error("Address type not supported")]
     AddressTypeNotSupported,
-    //    OtherReply(u8),
+    #[error("Unknown reply code `{0}`")]
+    OtherReply(u8),
 }
 
 #[rustfmt::skip]
@@ -78,8 +91,41 @@ pub mod consts {
     pub const SOCKS5_CMD_TCP_CONNECT:                  u8 = 0x01;
     pub const SOCKS5_CMD_TCP_BIND:                     u8 = 0x02;
     pub const SOCKS5_CMD_UDP_ASSOCIATE:                u8 = 0x03;
+    pub const SOCKS5_CMD_TOR_RESOLVE:                  u8 = 0xf0;
+    pub const SOCKS5_CMD_TOR_RESOLVE_PTR:              u8 = 0xf1;
+
+    pub const SOCKS5_AUTH_METHOD_NONE:                 u8 = 0x00;
+    pub const SOCKS5_AUTH_METHOD_PASSWORD:             u8 = 0x02;
+    pub const SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE:       u8 = 0xff;
+
+    /// Version of the username/password subnegotiation, as defined by RFC 1929.
+    pub const SOCKS5_AUTH_PASSWORD_VERSION:            u8 = 0x01;
+
+    pub const SOCKS5_ADDR_TYPE_IPV4:                   u8 = 0x01;
+    pub const SOCKS5_ADDR_TYPE_DOMAIN_NAME:            u8 = 0x03;
+    pub const SOCKS5_ADDR_TYPE_IPV6:                   u8 = 0x04;
 
     pub const SOCKS5_REPLY_SUCCEEDED:                  u8 = 0x00;
+    pub const SOCKS5_REPLY_GENERAL_FAILURE:            u8 = 0x01;
+    pub const SOCKS5_REPLY_CONNECTION_NOT_ALLOWED:     u8 = 0x02;
+    pub const SOCKS5_REPLY_NETWORK_UNREACHABLE:        u8 = 0x03;
+    pub const SOCKS5_REPLY_HOST_UNREACHABLE:           u8 = 0x04;
+    pub const SOCKS5_REPLY_CONNECTION_REFUSED:         u8 = 0x05;
+    pub const SOCKS5_REPLY_TTL_EXPIRED:                u8 = 0x06;
+    pub const SOCKS5_REPLY_COMMAND_NOT_SUPPORTED:      u8 = 0x07;
+    pub const SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+}
+
+#[allow(dead_code)]
+impl AuthenticationMethod {
+    #[inline]
+    #[rustfmt::skip]
+    fn as_u8(&self) -> u8 {
+        match self {
+            AuthenticationMethod::None              => consts::SOCKS5_AUTH_METHOD_NONE,
+            AuthenticationMethod::Password { .. }   => consts::SOCKS5_AUTH_METHOD_PASSWORD,
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -88,9 +134,11 @@ impl Socks5Command {
     #[rustfmt::skip]
     fn as_u8(&self) -> u8 {
         match self {
-            Socks5Command::TCPConnect   => consts::SOCKS5_CMD_TCP_CONNECT,
-            Socks5Command::TCPBind      => consts::SOCKS5_CMD_TCP_BIND,
-            Socks5Command::UDPAssociate => consts::SOCKS5_CMD_UDP_ASSOCIATE,
+            Socks5Command::TCPConnect    => consts::SOCKS5_CMD_TCP_CONNECT,
+            Socks5Command::TCPBind       => consts::SOCKS5_CMD_TCP_BIND,
+            Socks5Command::UDPAssociate  => consts::SOCKS5_CMD_UDP_ASSOCIATE,
+            Socks5Command::TorResolve    => consts::SOCKS5_CMD_TOR_RESOLVE,
+            Socks5Command::TorResolvePtr => consts::SOCKS5_CMD_TOR_RESOLVE_PTR,
         }
     }
 }
@@ -101,7 +149,15 @@ impl ReplyError {
     pub fn from_u8(code: u8) -> ReplyError {
         match code {
             consts::SOCKS5_REPLY_SUCCEEDED                  => ReplyError::Succeeded,
-            _                                               => unreachable!("ReplyError code unsupported."),
+            consts::SOCKS5_REPLY_GENERAL_FAILURE            => ReplyError::GeneralFailure,
+            consts::SOCKS5_REPLY_CONNECTION_NOT_ALLOWED     => ReplyError::ConnectionNotAllowed,
+            consts::SOCKS5_REPLY_NETWORK_UNREACHABLE        => ReplyError::NetworkUnreachable,
+            consts::SOCKS5_REPLY_HOST_UNREACHABLE           => ReplyError::HostUnreachable,
+            consts::SOCKS5_REPLY_CONNECTION_REFUSED         => ReplyError::ConnectionRefused,
+            consts::SOCKS5_REPLY_TTL_EXPIRED                => ReplyError::TtlExpired,
+            consts::SOCKS5_REPLY_COMMAND_NOT_SUPPORTED      => ReplyError::CommandNotSupported,
+            consts::SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED => ReplyError::AddressTypeNotSupported,
+            _                                               => ReplyError::OtherReply(code),
         }
     }
 }
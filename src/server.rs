@@ -0,0 +1,409 @@
+use anyhow::Context;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    consts,
+    read_exact,
+    util::{stream::tcp_connect, target_addr::TargetAddr},
+    AuthenticationMethod, ReplyError, Result, Socks5Command, SocksError,
+};
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+
+use tokio::net::{TcpListener, TcpStream};
+
+/// Called with the client's requested target before dialing upstream.
+/// Return `None` to reject the request, or `Some` to authorize it
+/// (optionally rewriting it to a different address).
+type AuthorizeFn = Arc<dyn Fn(&TargetAddr) -> Option<TargetAddr> + Send + Sync>;
+
+/// Server-side configuration: which authentication method is required from
+/// connecting clients, and how requested targets are authorized before the
+/// upstream dial.
+#[derive(Clone)]
+pub struct Config {
+    /// When set, require the RFC 1929 username/password subnegotiation and
+    /// check the client-supplied credentials against it. When `None`, only
+    /// the `NONE` method is accepted.
+    auth: Option<AuthenticationMethod>,
+    /// Called with the client's requested target before dialing upstream.
+    /// Return `None` to reject the request, or `Some` to authorize it
+    /// (optionally rewriting it to a different address).
+    authorize: AuthorizeFn,
+}
+
+impl std::fmt::Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Config").field("auth", &self.auth).finish()
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            auth: None,
+            authorize: Arc::new(|target_addr| Some(target_addr.clone())),
+        }
+    }
+}
+
+impl Config {
+    /// Require RFC 1929 username/password auth and accept only this pair.
+    pub fn set_authentication(&mut self, auth: AuthenticationMethod) -> &mut Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Set the hook called to authorize (and optionally rewrite) a client's
+    /// requested target before the upstream dial.
+    pub fn set_authorize<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&TargetAddr) -> Option<TargetAddr> + Send + Sync + 'static,
+    {
+        self.authorize = Arc::new(f);
+        self
+    }
+}
+
+/// A SOCKS5 server, accepting client connections and performing the inverse
+/// of [`Socks5Stream`](crate::client::Socks5Stream)'s handshake.
+pub struct Socks5Server {
+    listener: TcpListener,
+    config: Config,
+}
+
+impl Socks5Server {
+    /// Binds a listener and returns the server ready to `accept()` clients.
+    pub async fn bind<T>(addr: T, config: Config) -> Result<Self>
+    where
+        T: ToSocketAddrs,
+    {
+        let addr = addr.to_socket_addrs()?.next().context("unreachable")?;
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Socks5Server { listener, config })
+    }
+
+    /// Accepts one incoming connection. Call [`Socks5ServerStream::serve`] on
+    /// the result to run the handshake and dial the requested target.
+    pub async fn accept(&self) -> Result<(Socks5ServerStream<TcpStream>, SocketAddr)> {
+        let (socket, peer_addr) = self.listener.accept().await?;
+        info!("Accepted connection from {}", peer_addr);
+        Ok((
+            Socks5ServerStream::new(socket, self.config.clone()),
+            peer_addr,
+        ))
+    }
+}
+
+/// A client connection undergoing (or having completed) the server-side
+/// SOCKS5 handshake.
+pub struct Socks5ServerStream<S: AsyncRead + AsyncWrite + Unpin> {
+    socket: S,
+    config: Config,
+    target_addr: Option<TargetAddr>,
+}
+
+impl Socks5ServerStream<TcpStream> {
+    fn new(socket: TcpStream, config: Config) -> Self {
+        Socks5ServerStream {
+            socket,
+            config,
+            target_addr: None,
+        }
+    }
+
+    /// Runs the full server-side lifecycle: the auth handshake, the client's
+    /// CONNECT/BIND/UDP-ASSOCIATE request, and (for CONNECT) the upstream
+    /// dial. Returns `self` plus the connected upstream socket so the caller
+    /// can relay bytes between the two (e.g. with `tokio::io::copy_bidirectional`).
+    pub async fn serve(mut self) -> Result<(Self, TcpStream)> {
+        self.handshake().await?;
+        let (cmd, target_addr) = self.read_request().await?;
+
+        let target_addr = match (self.config.authorize)(&target_addr) {
+            Some(target_addr) => target_addr,
+            None => {
+                self.reply(ReplyError::ConnectionNotAllowed, &target_addr)
+                    .await?;
+                return Err(ReplyError::ConnectionNotAllowed.into());
+            }
+        };
+
+        // BIND and UDP ASSOCIATE aren't relayed yet, only CONNECT is.
+        if cmd != Socks5Command::TCPConnect {
+            self.reply(ReplyError::CommandNotSupported, &target_addr)
+                .await?;
+            return Err(ReplyError::CommandNotSupported.into());
+        }
+
+        let upstream_addr = match &target_addr {
+            TargetAddr::Ip(addr) => *addr,
+            TargetAddr::Domain(domain, port) => (domain.as_str(), *port)
+                .to_socket_addrs()?
+                .next()
+                .context("Can't resolve requested domain")?,
+        };
+
+        let upstream = match tcp_connect(upstream_addr).await {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                self.reply(ReplyError::HostUnreachable, &target_addr)
+                    .await?;
+                return Err(e);
+            }
+        };
+
+        let bind_addr = TargetAddr::Ip(upstream.local_addr()?);
+        self.reply(ReplyError::Succeeded, &bind_addr).await?;
+
+        Ok((self, upstream))
+    }
+
+    /// Consumes `self`, returning the underlying client socket.
+    pub fn into_inner(self) -> TcpStream {
+        self.socket
+    }
+
+    /// Reads the client's version/methods greeting and replies with the
+    /// selected method, running the RFC 1929 subnegotiation if required.
+    ///
+    /// # Request
+    /// ```test
+    ///          +----+----------+----------+
+    ///          |VER | NMETHODS | METHODS  |
+    ///          +----+----------+----------+
+    ///          | 1  |    1     | 1 to 255 |
+    ///          +----+----------+----------+
+    /// ```
+    async fn handshake(&mut self) -> Result<()> {
+        let [version, nmethods] =
+            read_exact!(self.socket, [0u8; 2]).context("Can't read version/methods header")?;
+        if version != consts::SOCKS5_VERSION {
+            return Err(SocksError::UnsupportedSocksVersion(version));
+        }
+
+        let mut methods = vec![0u8; nmethods as usize];
+        self.socket
+            .read_exact(&mut methods)
+            .await
+            .context("Can't read methods list")?;
+        debug!("Client offered methods: {:?}", &methods);
+
+        let selected = if self.config.auth.is_some() {
+            if methods.contains(&consts::SOCKS5_AUTH_METHOD_PASSWORD) {
+                consts::SOCKS5_AUTH_METHOD_PASSWORD
+            } else {
+                consts::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE
+            }
+        } else if methods.contains(&consts::SOCKS5_AUTH_METHOD_NONE) {
+            consts::SOCKS5_AUTH_METHOD_NONE
+        } else {
+            consts::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE
+        };
+
+        self.socket
+            .write_all(&[consts::SOCKS5_VERSION, selected])
+            .await
+            .context("Can't write method selection")?;
+        self.socket
+            .flush()
+            .await
+            .context("Can't flush method selection")?;
+
+        if selected == consts::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE {
+            return Err(SocksError::NoAcceptableAuthMethod);
+        }
+
+        if selected == consts::SOCKS5_AUTH_METHOD_PASSWORD {
+            self.password_subnegotiation().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the RFC 1929 username/password subnegotiation.
+    ///
+    /// # Request
+    /// ```test
+    ///          +----+------+----------+------+----------+
+    ///          |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+    ///          +----+------+----------+------+----------+
+    ///          | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+    ///          +----+------+----------+------+----------+
+    /// ```
+    async fn password_subnegotiation(&mut self) -> Result<()> {
+        let [_version, ulen] =
+            read_exact!(self.socket, [0u8; 2]).context("Can't read username length")?;
+        let mut username = vec![0u8; ulen as usize];
+        self.socket
+            .read_exact(&mut username)
+            .await
+            .context("Can't read username")?;
+
+        let [plen] = read_exact!(self.socket, [0u8; 1]).context("Can't read password length")?;
+        let mut password = vec![0u8; plen as usize];
+        self.socket
+            .read_exact(&mut password)
+            .await
+            .context("Can't read password")?;
+
+        let username = String::from_utf8(username)
+            .map_err(|_| anyhow::Error::msg("Username isn't valid UTF-8"))?;
+        let password = String::from_utf8(password)
+            .map_err(|_| anyhow::Error::msg("Password isn't valid UTF-8"))?;
+
+        let valid = matches!(
+            &self.config.auth,
+            Some(AuthenticationMethod::Password { username: u, password: p })
+                if u == &username && p == &password
+        );
+
+        self.socket
+            .write_all(&[
+                consts::SOCKS5_AUTH_PASSWORD_VERSION,
+                if valid { 0x00 } else { 0x01 },
+            ])
+            .await
+            .context("Can't write auth reply")?;
+        self.socket
+            .flush()
+            .await
+            .context("Can't flush auth reply")?;
+
+        if !valid {
+            return Err(SocksError::AuthenticationRejected);
+        }
+
+        Ok(())
+    }
+
+    /// Reads the client's CONNECT/BIND/UDP-ASSOCIATE request.
+    ///
+    /// # Request
+    /// ```test
+    ///          +----+-----+-------+------+----------+----------+
+    ///          |VER | CMD |  RSV  | ATYP | DST.ADDR | DST.PORT |
+    ///          +----+-----+-------+------+----------+----------+
+    ///          | 1  |  1  |   1   |  1   | Variable |    2     |
+    ///          +----+-----+-------+------+----------+----------+
+    /// ```
+    async fn read_request(&mut self) -> Result<(Socks5Command, TargetAddr)> {
+        let [version, cmd, _rsv, address_type] =
+            read_exact!(self.socket, [0u8; 4]).context("Malformed request header")?;
+        if version != consts::SOCKS5_VERSION {
+            return Err(SocksError::UnsupportedSocksVersion(version));
+        }
+
+        let cmd = match cmd {
+            consts::SOCKS5_CMD_TCP_CONNECT => Socks5Command::TCPConnect,
+            consts::SOCKS5_CMD_TCP_BIND => Socks5Command::TCPBind,
+            consts::SOCKS5_CMD_UDP_ASSOCIATE => Socks5Command::UDPAssociate,
+            _ => return Err(ReplyError::CommandNotSupported.into()),
+        };
+
+        let target_addr = match address_type {
+            consts::SOCKS5_ADDR_TYPE_IPV4 => {
+                let ip = std::net::Ipv4Addr::from(
+                    read_exact!(self.socket, [0u8; 4]).context("Malformed IPv4 address")?,
+                );
+                let port = u16::from_be_bytes(
+                    read_exact!(self.socket, [0u8; 2]).context("Malformed port")?,
+                );
+                TargetAddr::Ip(SocketAddr::V4(std::net::SocketAddrV4::new(ip, port)))
+            }
+            consts::SOCKS5_ADDR_TYPE_IPV6 => {
+                let ip = std::net::Ipv6Addr::from(
+                    read_exact!(self.socket, [0u8; 16]).context("Malformed IPv6 address")?,
+                );
+                let port = u16::from_be_bytes(
+                    read_exact!(self.socket, [0u8; 2]).context("Malformed port")?,
+                );
+                TargetAddr::Ip(SocketAddr::V6(std::net::SocketAddrV6::new(ip, port, 0, 0)))
+            }
+            consts::SOCKS5_ADDR_TYPE_DOMAIN_NAME => {
+                let [len] =
+                    read_exact!(self.socket, [0u8; 1]).context("Malformed domain length")?;
+                let mut domain = vec![0u8; len as usize];
+                self.socket
+                    .read_exact(&mut domain)
+                    .await
+                    .context("Malformed domain address")?;
+                let domain = String::from_utf8(domain)
+                    .map_err(|_| anyhow::Error::msg("Domain isn't valid UTF-8"))?;
+                let port = u16::from_be_bytes(
+                    read_exact!(self.socket, [0u8; 2]).context("Malformed port")?,
+                );
+                TargetAddr::Domain(domain, port)
+            }
+            _ => return Err(ReplyError::AddressTypeNotSupported.into()),
+        };
+
+        debug!("Request received: [cmd: {:?}, target: {:?}]", cmd, target_addr);
+        self.target_addr = Some(target_addr.clone());
+
+        Ok((cmd, target_addr))
+    }
+
+    /// Sends a reply to the client's request.
+    ///
+    /// # Response
+    /// ```test
+    ///          +----+-----+-------+------+----------+----------+
+    ///          |VER | REP |  RSV  | ATYP | BND.ADDR | BND.PORT |
+    ///          +----+-----+-------+------+----------+----------+
+    ///          | 1  |  1  |   1   |  1   | Variable |    2     |
+    ///          +----+-----+-------+------+----------+----------+
+    /// ```
+    async fn reply(&mut self, reply: ReplyError, bind_addr: &TargetAddr) -> Result<()> {
+        let code = match reply {
+            ReplyError::Succeeded => consts::SOCKS5_REPLY_SUCCEEDED,
+            ReplyError::GeneralFailure => consts::SOCKS5_REPLY_GENERAL_FAILURE,
+            ReplyError::ConnectionNotAllowed => consts::SOCKS5_REPLY_CONNECTION_NOT_ALLOWED,
+            ReplyError::NetworkUnreachable => consts::SOCKS5_REPLY_NETWORK_UNREACHABLE,
+            ReplyError::HostUnreachable => consts::SOCKS5_REPLY_HOST_UNREACHABLE,
+            ReplyError::ConnectionRefused => consts::SOCKS5_REPLY_CONNECTION_REFUSED,
+            // No dedicated wire code for a local timeout, report a general failure.
+            ReplyError::ConnectionTimeout => consts::SOCKS5_REPLY_GENERAL_FAILURE,
+            ReplyError::TtlExpired => consts::SOCKS5_REPLY_TTL_EXPIRED,
+            ReplyError::CommandNotSupported => consts::SOCKS5_REPLY_COMMAND_NOT_SUPPORTED,
+            ReplyError::AddressTypeNotSupported => consts::SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED,
+            ReplyError::OtherReply(code) => code,
+        };
+
+        let mut packet = vec![consts::SOCKS5_VERSION, code, 0x00];
+
+        match bind_addr {
+            TargetAddr::Ip(SocketAddr::V4(addr)) => {
+                packet.push(consts::SOCKS5_ADDR_TYPE_IPV4);
+                packet.extend_from_slice(&addr.ip().octets());
+                packet.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            TargetAddr::Ip(SocketAddr::V6(addr)) => {
+                packet.push(consts::SOCKS5_ADDR_TYPE_IPV6);
+                packet.extend_from_slice(&addr.ip().octets());
+                packet.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            TargetAddr::Domain(domain, port) => {
+                if domain.len() > u8::MAX as usize {
+                    return Err(SocksError::ExceededMaxDomainLen(domain.len()));
+                }
+                packet.push(consts::SOCKS5_ADDR_TYPE_DOMAIN_NAME);
+                packet.push(domain.len() as u8);
+                packet.extend_from_slice(domain.as_bytes());
+                packet.extend_from_slice(&port.to_be_bytes());
+            }
+        }
+
+        self.socket
+            .write_all(&packet)
+            .await
+            .context("Can't write reply packet")?;
+        self.socket
+            .flush()
+            .await
+            .context("Can't flush reply packet")?;
+
+        Ok(())
+    }
+}